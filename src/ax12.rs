@@ -1,14 +1,15 @@
-extern crate byteorder;
-
-use std::fmt;
-use std::io::Cursor;
+use core::fmt;
 
 use self::Access::*;
 use self::Size::*;
 use self::Register::*;
-use self::byteorder::{LittleEndian, ReadBytesExt};
 
-use super::packets::{Request, Status};
+use super::packets;
+use super::packets::Request;
+#[cfg(feature = "std")]
+use super::packets::Status;
+#[cfg(not(feature = "std"))]
+use super::packets::StatusBuf as Status;
 
 
 #[derive(Debug)]
@@ -41,6 +42,37 @@ pub struct RegisterInfo {
     pub size: Size,
 }
 
+/// Which control table a `Register` is resolved against. Addresses (and
+/// occasionally sizes) differ between servo generations, so each model
+/// carries its own table. The AX and MX tables here share every address
+/// and size; they differ in what some registers mean and in position
+/// resolution.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Model {
+    Ax,
+    Mx,
+}
+
+impl Model {
+    fn registers(&self) -> &'static [RegisterInfo] {
+        match *self {
+            Model::Ax => AX_REGISTER_INFO,
+            Model::Mx => MX_REGISTER_INFO,
+        }
+    }
+
+    /// Degrees of travel per raw position tick, for converting `Degrees`
+    /// register values to/from the wire representation. AX-12 covers 300°
+    /// over 1024 ticks; MX (protocol 1.0 firmware) covers 360° over 4096
+    /// ticks.
+    fn degrees_per_tick(&self) -> f32 {
+        match *self {
+            Model::Ax => 300.0 / 1024.0,
+            Model::Mx => 360.0 / 4096.0,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Register {
     ModelNumber,
@@ -104,7 +136,7 @@ pub static ALL_REGISTERS: &'static [Register] = &[
     PresentVoltage,
     PresentTemperature,
 ];
-static REGISTER_INFO: &'static [RegisterInfo] = &[
+static AX_REGISTER_INFO: &'static [RegisterInfo] = &[
     RegisterInfo {
         address: 0x00,
         size: HalfWord,
@@ -299,22 +331,206 @@ static REGISTER_INFO: &'static [RegisterInfo] = &[
     },
 ];
 
+/// MX-series control table. Same register set and addresses as
+/// `AX_REGISTER_INFO`, but the compliance-margin/slope registers are
+/// repurposed as the PID gains used by MX's position controller.
+static MX_REGISTER_INFO: &[RegisterInfo] = &[
+    RegisterInfo { address: 0x00, size: HalfWord, access: R, description: "Model number" },
+    RegisterInfo { address: 0x02, size: Byte, access: R, description: "Firmware version" },
+    RegisterInfo { address: 0x03, size: Byte, access: RW, description: "Actuator identifier" },
+    RegisterInfo { address: 0x04, size: Byte, access: RW, description: "Communication baud rate" },
+    RegisterInfo { address: 0x05, size: Byte, access: RW, description: "Return delay time" },
+    RegisterInfo { address: 0x06, size: HalfWord, access: RW, description: "Clockwise angle limit" },
+    RegisterInfo { address: 0x08, size: HalfWord, access: RW, description: "Counterclockwise angle limit" },
+    RegisterInfo { address: 0x0b, size: Byte, access: RW, description: "Temperature alarm level" },
+    RegisterInfo { address: 0x0c, size: Byte, access: RW, description: "Low voltage alarm level" },
+    RegisterInfo { address: 0x0d, size: Byte, access: RW, description: "High voltage alarm level" },
+    RegisterInfo { address: 0x0e, size: HalfWord, access: RW, description: "Max torque alarm level" },
+    RegisterInfo { address: 0x10, size: Byte, access: RW, description: "Status return level" },
+    RegisterInfo { address: 0x11, size: Byte, access: RW, description: "LED indication on alarm" },
+    RegisterInfo { address: 0x12, size: Byte, access: RW, description: "Shutdown on alarm" },
+    RegisterInfo { address: 0x18, size: Byte, access: RW, description: "Enable torque output" },
+    RegisterInfo { address: 0x19, size: Byte, access: RW, description: "Enable Led" },
+    RegisterInfo { address: 0x1a, size: Byte, access: RW, description: "D gain" },
+    RegisterInfo { address: 0x1b, size: Byte, access: RW, description: "I gain" },
+    RegisterInfo { address: 0x1c, size: Byte, access: RW, description: "P gain" },
+    RegisterInfo { address: 0x1d, size: Byte, access: RW, description: "Reserved" },
+    RegisterInfo { address: 0x1e, size: HalfWord, access: RW, description: "Goal position" },
+    RegisterInfo { address: 0x20, size: HalfWord, access: RW, description: "Moving speed" },
+    RegisterInfo { address: 0x22, size: HalfWord, access: RW, description: "Torque limit" },
+    RegisterInfo { address: 0x24, size: HalfWord, access: R, description: "Current position" },
+    RegisterInfo { address: 0x26, size: HalfWord, access: R, description: "Current speed" },
+    RegisterInfo { address: 0x28, size: HalfWord, access: R, description: "Current load" },
+    RegisterInfo { address: 0x2a, size: Byte, access: R, description: "Current voltage" },
+    RegisterInfo { address: 0x2b, size: Byte, access: R, description: "Current temperature" },
+    RegisterInfo { address: 0x2c, size: Byte, access: R, description: "Instruction registered" },
+    RegisterInfo { address: 0x2e, size: Byte, access: R, description: "Is Moving" },
+    RegisterInfo { address: 0x2f, size: Byte, access: RW, description: "EEPROM Lock" },
+    RegisterInfo { address: 0x30, size: HalfWord, access: RW, description: "Punch value" },
+];
+
+/// Physical-unit view of a register value, so callers don't have to
+/// remember that e.g. `PresentVoltage` is tenths of a volt. `RawTicks`
+/// covers registers with no natural physical unit (IDs, baud rate codes,
+/// load/torque percentages, ...).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterValue {
+    Degrees(f32),
+    Volts(f32),
+    Celsius(u8),
+    RawTicks(u16),
+}
+
 impl Register {
-    pub fn info(&self) -> &'static RegisterInfo {
-        &REGISTER_INFO[*self as usize]
+    pub fn info(&self, model: Model) -> &'static RegisterInfo {
+        &model.registers()[*self as usize]
     }
 
-    pub fn read_request(&self, id: u8) -> Request {
-        let info = self.info();
+    pub fn read_request(&self, id: u8, model: Model) -> Request {
+        let info = self.info(model);
         Request::Read { id: id, addr: info.address, len: info.size.len() as u8 }
     }
 
     pub fn parse_read_value(&self, status: Status) -> u16 {
-        if status.data.len() == 1 {
-            status.data[0] as u16
+        let data = status.data();
+        if data.len() == 1 {
+            data[0] as u16
         } else {
-            let mut rdr = Cursor::new(status.data);
-            rdr.read_u16::<LittleEndian>().unwrap()
+            (data[0] as u16) | ((data[1] as u16) << 8)
+        }
+    }
+
+    /// Converts a raw `Status` reply into the physical unit this register
+    /// is expressed in, scaling ticks to degrees per `model`'s resolution.
+    pub fn decode(&self, status: Status, model: Model) -> RegisterValue {
+        let raw = self.parse_read_value(status);
+        match *self {
+            CWAngleLimit | CCWAngleLimit | GoalPosition | PresentPosition =>
+                RegisterValue::Degrees(raw as f32 * model.degrees_per_tick()),
+            VoltageLimitLow | VoltageLimitHigh | PresentVoltage =>
+                RegisterValue::Volts(raw as f32 / 10.0),
+            TemperatureLimit | PresentTemperature => RegisterValue::Celsius(raw as u8),
+            _ => RegisterValue::RawTicks(raw),
+        }
+    }
+
+    /// Converts a physical-unit `value` back into the register's raw wire
+    /// representation, scaling degrees to ticks per `model`'s resolution,
+    /// e.g. for building a `Write` request.
+    pub fn encode(&self, value: RegisterValue, model: Model) -> u16 {
+        // `f32::round` lives in `std`, not `core` (it needs a software
+        // implementation on targets without a hardware rounding
+        // instruction), so round half up by hand instead — every value
+        // this crate encodes is non-negative.
+        match value {
+            RegisterValue::Degrees(deg) => (deg / model.degrees_per_tick() + 0.5) as u16,
+            RegisterValue::Volts(v) => (v * 10.0 + 0.5) as u16,
+            RegisterValue::Celsius(c) => c as u16,
+            RegisterValue::RawTicks(t) => t,
         }
     }
+
+    pub fn write_request(&self, id: u8, model: Model, value: RegisterValue) -> packets::Result<Request> {
+        let info = self.info(model);
+        let raw = self.encode(value, model);
+        let data = [(raw & 0xff) as u8, (raw >> 8) as u8];
+        let data = match info.size {
+            Size::Byte => &data[..1],
+            Size::HalfWord => &data[..2],
+        };
+        Request::write(id, info.address, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goal_position_resolves_to_the_same_address_on_ax_and_mx() {
+        assert_eq!(Register::GoalPosition.info(Model::Ax).address, 0x1e);
+        assert_eq!(Register::GoalPosition.info(Model::Mx).address, 0x1e);
+    }
+
+    #[test]
+    fn compliance_margin_is_repurposed_as_d_gain_in_mx_control_table() {
+        // Same address on both models, but MX repurposes it as a PID gain
+        // rather than a compliance margin.
+        let ax = Register::CwComplianceMargin.info(Model::Ax);
+        let mx = Register::CwComplianceMargin.info(Model::Mx);
+        assert_eq!(ax.address, 0x1a);
+        assert_eq!(mx.address, 0x1a);
+        assert_eq!(ax.description, "Clockwise compliance margin");
+        assert_eq!(mx.description, "D gain");
+    }
+
+    #[test]
+    fn present_position_is_a_read_only_half_word_on_both_models() {
+        for model in &[Model::Ax, Model::Mx] {
+            let info = Register::PresentPosition.info(*model);
+            assert_eq!(info.address, 0x24);
+            assert_eq!(info.size.len(), 2);
+            assert!(matches!(info.access, Access::R));
+        }
+    }
+
+    // `Status` is a plain struct under `feature = "std"` but an alias for
+    // `StatusBuf` (whose fields are private) otherwise, so these can't be
+    // built with a struct literal in a `no_std` build.
+    #[cfg(feature = "std")]
+    fn status_of(raw: u16) -> Status {
+        Status { id: 1, error: 0, data: vec![(raw & 0xff) as u8, (raw >> 8) as u8] }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn goal_position_decode_encode_round_trips_ticks_per_model() {
+        for model in &[Model::Ax, Model::Mx] {
+            let raw: u16 = 512;
+            match Register::GoalPosition.decode(status_of(raw), *model) {
+                RegisterValue::Degrees(deg) => {
+                    assert_eq!(Register::GoalPosition.encode(RegisterValue::Degrees(deg), *model), raw);
+                }
+                other => panic!("expected Degrees, got {:?}", other),
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn ax_and_mx_scale_the_same_raw_ticks_to_different_degrees() {
+        let raw: u16 = 512;
+        let ax_degrees = match Register::GoalPosition.decode(status_of(raw), Model::Ax) {
+            RegisterValue::Degrees(deg) => deg,
+            other => panic!("expected Degrees, got {:?}", other),
+        };
+        let mx_degrees = match Register::GoalPosition.decode(status_of(raw), Model::Mx) {
+            RegisterValue::Degrees(deg) => deg,
+            other => panic!("expected Degrees, got {:?}", other),
+        };
+
+        assert!((ax_degrees - 150.0).abs() < 0.01);
+        assert!((mx_degrees - 45.0).abs() < 0.01);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn present_voltage_decode_encode_round_trips_tenths_of_a_volt() {
+        let raw: u16 = 118; // 11.8V
+        match Register::PresentVoltage.decode(status_of(raw), Model::Ax) {
+            RegisterValue::Volts(v) => {
+                assert!((v - 11.8).abs() < 0.001);
+                assert_eq!(Register::PresentVoltage.encode(RegisterValue::Volts(v), Model::Ax), raw);
+            }
+            other => panic!("expected Volts, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn moving_speed_decodes_as_raw_ticks_with_no_unit_conversion() {
+        let raw: u16 = 300;
+        assert_eq!(Register::MovingSpeed.decode(status_of(raw), Model::Ax), RegisterValue::RawTicks(raw));
+        assert_eq!(Register::MovingSpeed.encode(RegisterValue::RawTicks(raw), Model::Ax), raw);
+    }
 }
\ No newline at end of file
@@ -1,6 +1,10 @@
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
+use std::future::Future;
 use std::io::{Read, Write};
-use std::{thread, time};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time;
 use std;
 use std::fmt;
 
@@ -8,6 +12,7 @@ extern crate serial;
 
 use self::serial::prelude::*;
 
+use super::ax12;
 use super::packets;
 
 
@@ -30,8 +35,27 @@ pub enum Error {
 
     // Cannot parse response
     DataError(packets::Error),
+
+    // Gave up waiting for (all of) the expected response(s)
+    Timeout,
+
+    // `exchange`/`exchange_many` was called with a request that doesn't
+    // have the reply cardinality they assume (one `Status`, or one per
+    // expected ID) — e.g. a `SyncWrite`, which real hardware never
+    // replies to. Use `HalfDuplex::send` instead.
+    WrongExchangeMethod,
+}
+
+/// Minimal `nb`-style result: distinguishes "not ready yet, poll again"
+/// from a genuine error, without pulling in the `nb` crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NbError<E> {
+    WouldBlock,
+    Other(E),
 }
 
+pub type NbResult<T, E> = std::result::Result<T, NbError<E>>;
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -40,28 +64,89 @@ impl fmt::Display for Error {
             &Error::WriteError(e) => write!(f, "write failed: {:?}", e),
             &Error::DataError(e) => write!(f, "cannot parse response: {:?}", e),
             &Error::TransferError => write!(f, "not all data transferred"),
+            &Error::Timeout => write!(f, "timed out waiting for response"),
+            &Error::WrongExchangeMethod => write!(f, "request has no single Status reply; use HalfDuplex::send"),
         }
     }
 }
 
-pub struct Bus {
-    port: serial::SystemPort
+/// Default overall reply timeout used by `Bus::open`/`open_with_protocol`.
+/// Override with `Bus::open_with_timeout` for slower or faster links.
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// Default maximum gap allowed between consecutive bytes of a reply before
+/// it's considered dead, used by `Bus::open`/`open_with_protocol`.
+const DEFAULT_INTER_BYTE_GAP_MS: u64 = 100;
+
+/// Tracks an in-flight `NonBlocking::poll_exchange` so repeated polls with
+/// the same request can pick up where the last one left off instead of
+/// re-writing it. `request` is the serialized frame that was written, used
+/// to detect a caller abandoning one exchange for another (e.g. an outer
+/// `select!`/`timeout` dropping the `Exchange` future) so the new request
+/// gets written instead of silently resuming the stale one.
+enum PendingExchange {
+    None,
+    AwaitingReply { request: Vec<u8>, buf: Vec<u8>, started_at: time::Instant, last_byte_at: time::Instant },
+}
+
+/// `Bus` is generic over its underlying port so tests can swap
+/// `serial::SystemPort` for an in-memory `Read + Write` fake; real callers
+/// only ever see the default `Bus` (= `Bus<serial::SystemPort>`) built by
+/// `Bus::open`/`open_with_protocol`/`open_with_timeout`.
+pub struct Bus<P: Read + Write = serial::SystemPort> {
+    port: P,
+    protocol: packets::Protocol,
+    timeout: time::Duration,
+    inter_byte_gap: time::Duration,
+    pending: PendingExchange,
+    models: HashMap<u8, ax12::Model>,
 }
 
 pub trait HalfDuplex {
+    /// Writes `p` and waits for exactly one `Status` reply. Real hardware
+    /// never replies to a `SyncWrite`, and a `BulkRead` replies once per
+    /// entry rather than once overall, so both are rejected with
+    /// `Error::WrongExchangeMethod` — use `exchange_many` for `BulkRead`
+    /// and `send` for `SyncWrite`.
     fn exchange(&mut self, p: &packets::Request) -> Result<packets::Status>;
+
+    /// Sends `p` (typically a `BulkRead`) and collects one `Status` per ID
+    /// in `expected_ids`, draining the port until all of them have
+    /// answered or the timeout elapses.
+    fn exchange_many(&mut self, p: &packets::Request, expected_ids: &[u8]) -> Result<Vec<packets::Status>>;
+
+    /// Writes `p` without waiting for a reply, for requests like
+    /// `SyncWrite` that real hardware never answers.
+    fn send(&mut self, p: &packets::Request) -> Result<()>;
 }
 
-impl HalfDuplex for Bus {
+/// Rejects requests that don't reply with exactly one `Status` — shared by
+/// every single-reply entry point (`HalfDuplex::exchange`,
+/// `NonBlocking::poll_exchange`, and the `AsyncHalfDuplex::exchange_async`
+/// future built on top of it) so none of them busy-wait out a full timeout
+/// on a `SyncWrite`/`BulkRead` that real hardware was never going to answer
+/// the way they expect.
+fn check_single_reply(p: &packets::Request) -> Result<()> {
+    match *p {
+        packets::Request::SyncWrite { .. } | packets::Request::BulkRead { .. } => {
+            Err(Error::WrongExchangeMethod)
+        }
+        _ => Ok(()),
+    }
+}
+
+impl<P: Read + Write> HalfDuplex for Bus<P> {
     fn exchange(&mut self, p: &packets::Request) -> Result<packets::Status> {
-        let request_data = p.serialized();
+        check_single_reply(p)?;
+
+        let request_data = p.serialized(self.protocol);
 
         match self.port.write(request_data.as_slice()) {
             Err(err) => Err(Error::WriteError(err.kind())),
             Ok(len) if len != request_data.len() => Err(Error::TransferError),
             Ok(_) => {
                 match self.read_packet() {
-                    Ok(data) => match packets::Status::from_bytes(data.as_slice()) {
+                    Ok(data) => match packets::Status::from_bytes(data.as_slice(), self.protocol) {
                         Ok(s) => Ok(s),
                         Err(e) => {
                             Err(Error::DataError(e))
@@ -72,42 +157,401 @@ impl HalfDuplex for Bus {
             }
         }
     }
+
+    fn exchange_many(&mut self, p: &packets::Request, expected_ids: &[u8]) -> Result<Vec<packets::Status>> {
+        if let packets::Request::SyncWrite { .. } = *p {
+            return Err(Error::WrongExchangeMethod);
+        }
+
+        let request_data = p.serialized(self.protocol);
+
+        match self.port.write(request_data.as_slice()) {
+            Err(err) => Err(Error::WriteError(err.kind())),
+            Ok(len) if len != request_data.len() => Err(Error::TransferError),
+            Ok(_) => self.read_packets(expected_ids),
+        }
+    }
+
+    fn send(&mut self, p: &packets::Request) -> Result<()> {
+        let request_data = p.serialized(self.protocol);
+
+        match self.port.write(request_data.as_slice()) {
+            Err(err) => Err(Error::WriteError(err.kind())),
+            Ok(len) if len != request_data.len() => Err(Error::TransferError),
+            Ok(_) => Ok(()),
+        }
+    }
 }
 
-impl Bus {
+impl Bus<serial::SystemPort> {
     pub fn open<T: AsRef<OsStr> + ? Sized>(port: &T, baud: u32) -> Result<Bus> {
+        Bus::open_with_protocol(port, baud, packets::Protocol::V1)
+    }
+
+    pub fn open_with_protocol<T: AsRef<OsStr> + ? Sized>(port: &T, baud: u32, protocol: packets::Protocol) -> Result<Bus> {
+        Bus::open_with_timeout(
+            port, baud, protocol,
+            time::Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            time::Duration::from_millis(DEFAULT_INTER_BYTE_GAP_MS),
+        )
+    }
+
+    /// Opens the port with an explicit overall reply `timeout` and maximum
+    /// `inter_byte_gap` between consecutive bytes of a reply; once either
+    /// elapses, a pending exchange fails with `Error::Timeout` instead of
+    /// blocking forever.
+    pub fn open_with_timeout<T: AsRef<OsStr> + ? Sized>(
+        port: &T, baud: u32, protocol: packets::Protocol,
+        timeout: time::Duration, inter_byte_gap: time::Duration,
+    ) -> Result<Bus> {
         let mut p = serial::open(port).map_err(|e| Error::SerialError(e.kind()))?;
 
         let mut s = serial::PortSettings::default();
         s = serial::PortSettings { baud_rate: serial::BaudRate::from_speed(baud as usize), ..s };
         p.configure(&s).unwrap();
-        Ok(Bus { port: p })
+        // Keep reads from blocking past the inter-byte gap, so the polling
+        // loops below can actually observe the deadline instead of being
+        // stuck inside a single `read` call.
+        p.set_timeout(inter_byte_gap).map_err(|e| Error::SerialError(e.kind()))?;
+
+        Ok(Bus {
+            port: p,
+            protocol,
+            timeout,
+            inter_byte_gap,
+            pending: PendingExchange::None,
+            models: HashMap::new(),
+        })
+    }
+}
+
+impl<P: Read + Write> Bus<P> {
+    /// Records which control table to resolve `Register`s against for `id`.
+    /// IDs with no recorded model default to `Model::Ax`.
+    pub fn set_model(&mut self, id: u8, model: ax12::Model) {
+        self.models.insert(id, model);
+    }
+
+    pub fn model_for(&self, id: u8) -> ax12::Model {
+        *self.models.get(&id).unwrap_or(&ax12::Model::Ax)
+    }
+
+    /// Reads `register` from servo `id`, resolving its address against
+    /// whatever model was last passed to `set_model` for that ID.
+    pub fn read_register(&mut self, id: u8, register: ax12::Register) -> Result<packets::Status> {
+        let model = self.model_for(id);
+        self.exchange(&register.read_request(id, model))
+    }
+
+    /// Writes `value` to `register` on servo `id`, converting from its
+    /// physical unit and resolving the address against whatever model was
+    /// last passed to `set_model` for that ID.
+    pub fn write_register(&mut self, id: u8, register: ax12::Register, value: ax12::RegisterValue) -> Result<packets::Status> {
+        let model = self.model_for(id);
+        let request = register.write_request(id, model, value).map_err(Error::DataError)?;
+        self.exchange(&request)
     }
 
     fn read_packet(&mut self) -> Result<Vec<u8>> {
+        let deadline = time::Instant::now() + self.timeout;
         let mut output: Vec<u8> = Vec::new();
-        let mut local_buf: &mut [u8] = &mut [0; 128];
+        let mut last_byte_at = time::Instant::now();
+        let local_buf: &mut [u8] = &mut [0; 128];
         loop {
+            let now = time::Instant::now();
+            if now >= deadline || (!output.is_empty() && now.duration_since(last_byte_at) >= self.inter_byte_gap) {
+                break Err(Error::Timeout);
+            }
+
             match self.port.read(local_buf) {
-                Ok(size) if size == 0 => {
-                    break Err(Error::TransferError);
-                },
+                Ok(0) => {
+                    // `port.read` is configured to time out after
+                    // `inter_byte_gap`, so this just means "nothing yet".
+                }
                 Ok(size) => {
                     info!("Read {} bytes", size);
                     output.extend(local_buf[..size].as_ref());
-                    if packets::Status::is_constructible_from(output.as_slice()) {
+                    last_byte_at = time::Instant::now();
+                    if packets::is_constructible_from(output.as_slice(), self.protocol) {
                         info!("Packet complete");
                         break Ok(output);
-                    } else {
-                        thread::sleep(time::Duration::from_millis(1));
                     }
                 },
+                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    // Port-level read timeout, not a real error; loop back
+                    // around to re-check our own deadline/inter-byte gap.
+                }
                 Err(e) => {
                     break Err(Error::ReadError(e.kind()));
                 }
             }
         }
     }
+
+    fn read_packets(&mut self, expected_ids: &[u8]) -> Result<Vec<packets::Status>> {
+        let deadline = time::Instant::now() + self.timeout;
+        let mut buf: Vec<u8> = Vec::new();
+        let mut last_byte_at = time::Instant::now();
+        let local_buf: &mut [u8] = &mut [0; 128];
+        let mut statuses: Vec<packets::Status> = Vec::new();
+        let mut remaining_ids: HashSet<u8> = expected_ids.iter().cloned().collect();
+
+        while !remaining_ids.is_empty() {
+            let now = time::Instant::now();
+            if now >= deadline || (!buf.is_empty() && now.duration_since(last_byte_at) >= self.inter_byte_gap) {
+                return Err(Error::Timeout);
+            }
+
+            match self.port.read(local_buf) {
+                Ok(0) => {
+                    // `port.read` is configured to time out after
+                    // `inter_byte_gap`, so this just means "nothing yet".
+                }
+                Ok(size) => {
+                    info!("Read {} bytes", size);
+                    buf.extend(local_buf[..size].as_ref());
+                    last_byte_at = time::Instant::now();
+                    while let Some(len) = packets::declared_length(buf.as_slice(), self.protocol) {
+                        if buf.len() < len {
+                            break;
+                        }
+                        let packet: Vec<u8> = buf.drain(..len).collect();
+                        match packets::Status::from_bytes(packet.as_slice(), self.protocol) {
+                            Ok(s) => {
+                                if remaining_ids.remove(&s.id) {
+                                    statuses.push(s);
+                                }
+                            }
+                            Err(e) => return Err(Error::DataError(e)),
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    // Port-level read timeout, not a real error; loop back
+                    // around to re-check our own deadline/inter-byte gap.
+                }
+                Err(e) => {
+                    return Err(Error::ReadError(e.kind()));
+                }
+            }
+        }
+
+        Ok(statuses)
+    }
 }
 
+/// Non-blocking variant of `HalfDuplex`: never sleeps, and makes at most
+/// one port read per call, returning `NbError::WouldBlock` until the reply
+/// (or a timeout) is ready. On a `Bus` built by `Bus::open_with_timeout`
+/// that read can itself wait up to `inter_byte_gap` for data. Repeated
+/// calls with the same `p` resume the pending exchange rather than
+/// re-writing the request.
+pub trait NonBlocking {
+    fn poll_exchange(&mut self, p: &packets::Request) -> NbResult<packets::Status, Error>;
+}
+
+impl<P: Read + Write> NonBlocking for Bus<P> {
+    fn poll_exchange(&mut self, p: &packets::Request) -> NbResult<packets::Status, Error> {
+        check_single_reply(p).map_err(NbError::Other)?;
+
+        let request_data = p.serialized(self.protocol);
+        let pending = std::mem::replace(&mut self.pending, PendingExchange::None);
+        let (mut buf, started_at, last_byte_at) = match pending {
+            PendingExchange::AwaitingReply { request, buf, started_at, last_byte_at } if request == request_data => {
+                (buf, started_at, last_byte_at)
+            }
+            PendingExchange::None | PendingExchange::AwaitingReply { .. } => {
+                match self.port.write(request_data.as_slice()) {
+                    Err(err) => return Err(NbError::Other(Error::WriteError(err.kind()))),
+                    Ok(len) if len != request_data.len() => return Err(NbError::Other(Error::TransferError)),
+                    Ok(_) => {}
+                }
+                let now = time::Instant::now();
+                (Vec::new(), now, now)
+            }
+        };
+
+        let now = time::Instant::now();
+        if now.duration_since(started_at) >= self.timeout
+            || (!buf.is_empty() && now.duration_since(last_byte_at) >= self.inter_byte_gap)
+        {
+            return Err(NbError::Other(Error::Timeout));
+        }
+
+        let mut local_buf: [u8; 128] = [0; 128];
+        match self.port.read(&mut local_buf) {
+            Ok(0) => {
+                self.pending = PendingExchange::AwaitingReply { request: request_data, buf, started_at, last_byte_at };
+                Err(NbError::WouldBlock)
+            }
+            Ok(size) => {
+                buf.extend(local_buf[..size].as_ref());
+                let last_byte_at = time::Instant::now();
+                if packets::is_constructible_from(buf.as_slice(), self.protocol) {
+                    match packets::Status::from_bytes(buf.as_slice(), self.protocol) {
+                        Ok(s) => Ok(s),
+                        Err(e) => Err(NbError::Other(Error::DataError(e))),
+                    }
+                } else {
+                    self.pending = PendingExchange::AwaitingReply { request: request_data, buf, started_at, last_byte_at };
+                    Err(NbError::WouldBlock)
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut || e.kind() == std::io::ErrorKind::WouldBlock => {
+                self.pending = PendingExchange::AwaitingReply { request: request_data, buf, started_at, last_byte_at };
+                Err(NbError::WouldBlock)
+            }
+            Err(e) => Err(NbError::Other(Error::ReadError(e.kind()))),
+        }
+    }
+}
+
+/// Future returned by `AsyncHalfDuplex::exchange_async`. There's no I/O
+/// reactor to register a waker with, so `poll` just re-wakes itself on
+/// `WouldBlock`, turning the non-blocking state machine into something an
+/// async executor can busy-drive.
+pub struct Exchange<'a, P: Read + Write = serial::SystemPort> {
+    bus: &'a mut Bus<P>,
+    request: &'a packets::Request,
+}
+
+impl<'a, P: Read + Write> Future for Exchange<'a, P> {
+    type Output = Result<packets::Status>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.bus.poll_exchange(this.request) {
+            Ok(status) => Poll::Ready(Ok(status)),
+            Err(NbError::WouldBlock) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Err(NbError::Other(e)) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+pub trait AsyncHalfDuplex<P: Read + Write = serial::SystemPort> {
+    fn exchange_async<'a>(&'a mut self, p: &'a packets::Request) -> Exchange<'a, P>;
+}
+
+impl<P: Read + Write> AsyncHalfDuplex<P> for Bus<P> {
+    fn exchange_async<'a>(&'a mut self, p: &'a packets::Request) -> Exchange<'a, P> {
+        Exchange { bus: self, request: p }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io;
+
+    /// In-memory `Read + Write` stand-in for `serial::SystemPort`, so
+    /// `read_packet`/`poll_exchange` can be driven with a scripted byte
+    /// stream instead of real hardware. Reads are served one queued chunk
+    /// per call, mimicking how a real port's `read` returns whatever
+    /// arrived since the last call; an empty queue reports a port-level
+    /// timeout, same as a `serial` port configured with `set_timeout`.
+    struct FakePort {
+        chunks: VecDeque<Vec<u8>>,
+        writes: usize,
+    }
+
+    impl FakePort {
+        fn new() -> FakePort {
+            FakePort { chunks: VecDeque::new(), writes: 0 }
+        }
+
+        fn push_chunk(&mut self, data: Vec<u8>) {
+            self.chunks.push_back(data);
+        }
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.chunks.pop_front() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                None => Err(io::Error::new(io::ErrorKind::TimedOut, "no data queued")),
+            }
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_bus(port: FakePort) -> Bus<FakePort> {
+        Bus {
+            port,
+            protocol: packets::Protocol::V1,
+            timeout: time::Duration::from_millis(50),
+            inter_byte_gap: time::Duration::from_millis(20),
+            pending: PendingExchange::None,
+            models: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn read_packet_times_out_when_nothing_arrives() {
+        let mut bus = test_bus(FakePort::new());
+
+        assert_eq!(bus.read_packet().err(), Some(Error::Timeout));
+    }
+
+    #[test]
+    fn poll_exchange_rejects_sync_write_and_bulk_read_without_touching_the_port() {
+        let mut bus = test_bus(FakePort::new());
+
+        let sync_write = packets::Request::SyncWrite { addr: 0x1e, len: 2, entries: vec![(1, vec![0x01, 0x00])] };
+        let bulk_read = packets::Request::BulkRead { entries: vec![(1, 0x24, 2)] };
+
+        assert_eq!(bus.poll_exchange(&sync_write), Err(NbError::Other(Error::WrongExchangeMethod)));
+        assert_eq!(bus.poll_exchange(&bulk_read), Err(NbError::Other(Error::WrongExchangeMethod)));
+        assert_eq!(bus.port.writes, 0);
+    }
+
+    #[test]
+    fn poll_exchange_resumes_a_pending_reply_instead_of_rewriting_the_request() {
+        // A Protocol 1.0 Status{id: 1, error: 0x24, data: []} reply, split
+        // across two port reads the way a real UART would deliver it.
+        let mut port = FakePort::new();
+        port.push_chunk(vec![0xff, 0xff, 0x01]);
+        port.push_chunk(vec![0x02, 0x24, 0xd8]);
+        let mut bus = test_bus(port);
+
+        let request = packets::Request::Ping { id: 1 };
+
+        assert_eq!(bus.poll_exchange(&request), Err(NbError::WouldBlock));
+        assert_eq!(bus.port.writes, 1);
+
+        let status = bus.poll_exchange(&request).expect("second chunk completes the reply");
+        assert_eq!(status, packets::Status { id: 1, error: 0x24, data: vec![] });
+        assert_eq!(bus.port.writes, 1, "resuming a pending exchange must not rewrite the request");
+    }
+
+    #[test]
+    fn poll_exchange_restarts_when_the_pending_request_changes() {
+        let mut bus = test_bus(FakePort::new());
+
+        let first = packets::Request::Ping { id: 1 };
+        let second = packets::Request::Ping { id: 2 };
+
+        assert_eq!(bus.poll_exchange(&first), Err(NbError::WouldBlock));
+        assert_eq!(bus.port.writes, 1);
+
+        assert_eq!(bus.poll_exchange(&second), Err(NbError::WouldBlock));
+        assert_eq!(bus.port.writes, 2, "an abandoned exchange must be rewritten, not resumed");
+    }
+}
 
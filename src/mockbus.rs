@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use super::bus::{Error, HalfDuplex, Result};
+use super::packets;
+use super::packets::{Request, Status};
+
+/// What a seeded servo should do on its next exchange, for exercising
+/// error paths without real hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    InvalidCrc,
+    Timeout,
+}
+
+struct Servo {
+    memory: [u8; 256],
+    fault: Option<Fault>,
+}
+
+impl Servo {
+    fn new() -> Servo {
+        Servo { memory: [0; 256], fault: None }
+    }
+}
+
+/// In-memory stand-in for `Bus`: holds a register file per servo ID,
+/// answers `Request`s the way real hardware would, and can be seeded to
+/// fail on demand. Lets downstream motion-control code (and this crate's
+/// own round-trip tests) run without a real serial port.
+pub struct MockBus {
+    servos: HashMap<u8, Servo>,
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        MockBus::new()
+    }
+}
+
+impl MockBus {
+    pub fn new() -> MockBus {
+        MockBus { servos: HashMap::new() }
+    }
+
+    /// Adds a servo at `id` with all registers zeroed.
+    pub fn add_servo(&mut self, id: u8) {
+        self.servos.insert(id, Servo::new());
+    }
+
+    /// Makes the next exchange addressed to `id` fail with `fault` instead
+    /// of being answered normally.
+    pub fn inject_fault(&mut self, id: u8, fault: Fault) {
+        if let Some(servo) = self.servos.get_mut(&id) {
+            servo.fault = Some(fault);
+        }
+    }
+
+    fn take_fault(&mut self, id: u8) -> Option<Fault> {
+        self.servos.get_mut(&id).and_then(|s| s.fault.take())
+    }
+
+    fn fault_err(fault: Fault) -> Error {
+        match fault {
+            Fault::InvalidCrc => Error::DataError(packets::Error::InvalidCrc),
+            Fault::Timeout => Error::Timeout,
+        }
+    }
+
+    fn read(&self, id: u8, addr: u8, len: u8) -> Vec<u8> {
+        let start = addr as usize;
+        let end = start + len as usize;
+        match self.servos.get(&id) {
+            Some(servo) if end <= servo.memory.len() => servo.memory[start..end].to_vec(),
+            // Unknown servo, or an out-of-range address/length: real
+            // hardware wouldn't have these registers either, so answer
+            // with zeros rather than panicking on a bad slice index.
+            _ => vec![0; len as usize],
+        }
+    }
+
+    fn write(&mut self, id: u8, addr: u8, data: &[u8]) {
+        if let Some(servo) = self.servos.get_mut(&id) {
+            for (offset, byte) in data.iter().enumerate() {
+                if let Some(slot) = servo.memory.get_mut(addr as usize + offset) {
+                    *slot = *byte;
+                }
+            }
+        }
+    }
+
+    fn status(id: u8, data: Vec<u8>) -> Status {
+        Status { id, error: 0, data }
+    }
+}
+
+impl HalfDuplex for MockBus {
+    fn exchange(&mut self, p: &Request) -> Result<Status> {
+        match *p {
+            Request::Ping { id } => {
+                if let Some(fault) = self.take_fault(id) {
+                    return Err(MockBus::fault_err(fault));
+                }
+                Ok(MockBus::status(id, Vec::new()))
+            }
+            Request::Read { id, addr, len } => {
+                if let Some(fault) = self.take_fault(id) {
+                    return Err(MockBus::fault_err(fault));
+                }
+                let data = self.read(id, addr, len);
+                Ok(MockBus::status(id, data))
+            }
+            Request::Write { id, addr, ref data } => {
+                if let Some(fault) = self.take_fault(id) {
+                    return Err(MockBus::fault_err(fault));
+                }
+                self.write(id, addr, data.as_slice());
+                Ok(MockBus::status(id, Vec::new()))
+            }
+            Request::SyncWrite { .. } | Request::BulkRead { .. } => Err(Error::WrongExchangeMethod),
+        }
+    }
+
+    fn exchange_many(&mut self, p: &Request, _expected_ids: &[u8]) -> Result<Vec<Status>> {
+        match *p {
+            Request::BulkRead { ref entries } => {
+                let mut statuses = Vec::new();
+                for &(id, addr, len) in entries {
+                    if let Some(fault) = self.take_fault(id) {
+                        return Err(MockBus::fault_err(fault));
+                    }
+                    let data = self.read(id, addr, len);
+                    statuses.push(MockBus::status(id, data));
+                }
+                Ok(statuses)
+            }
+            _ => self.exchange(p).map(|status| vec![status]),
+        }
+    }
+
+    fn send(&mut self, p: &Request) -> Result<()> {
+        match *p {
+            Request::SyncWrite { addr, ref entries, .. } => {
+                for &(id, ref data) in entries {
+                    if let Some(fault) = self.take_fault(id) {
+                        return Err(MockBus::fault_err(fault));
+                    }
+                    self.write(id, addr, data.as_slice());
+                }
+                Ok(())
+            }
+            _ => self.exchange(p).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus_with_servo(id: u8) -> MockBus {
+        let mut bus = MockBus::new();
+        bus.add_servo(id);
+        bus
+    }
+
+    #[test]
+    fn write_then_read_round_trips_through_memory() {
+        let mut bus = bus_with_servo(1);
+
+        bus.exchange(&Request::Write { id: 1, addr: 0x1e, data: vec![0x12, 0x03] }).unwrap();
+        let status = bus.exchange(&Request::Read { id: 1, addr: 0x1e, len: 2 }).unwrap();
+
+        assert_eq!(status.data, vec![0x12, 0x03]);
+    }
+
+    #[test]
+    fn read_of_unknown_servo_returns_zeroed_data() {
+        let mut bus = MockBus::new();
+
+        let status = bus.exchange(&Request::Read { id: 9, addr: 0x24, len: 2 }).unwrap();
+
+        assert_eq!(status.data, vec![0, 0]);
+    }
+
+    #[test]
+    fn read_past_end_of_memory_returns_zeroed_data_instead_of_panicking() {
+        let mut bus = bus_with_servo(1);
+
+        let status = bus.exchange(&Request::Read { id: 1, addr: 0xfe, len: 4 }).unwrap();
+
+        assert_eq!(status.data, vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn write_past_end_of_memory_does_not_panic() {
+        let mut bus = bus_with_servo(1);
+
+        bus.exchange(&Request::Write { id: 1, addr: 0xfe, data: vec![0x01, 0x02, 0x03, 0x04] }).unwrap();
+    }
+
+    #[test]
+    fn injected_fault_fires_once() {
+        let mut bus = bus_with_servo(1);
+        bus.inject_fault(1, Fault::Timeout);
+
+        let first = bus.exchange(&Request::Ping { id: 1 });
+        let second = bus.exchange(&Request::Ping { id: 1 });
+
+        assert_eq!(first, Err(Error::Timeout));
+        assert!(second.is_ok());
+    }
+
+    #[test]
+    fn sync_write_applies_every_entry() {
+        let mut bus = bus_with_servo(1);
+        bus.add_servo(2);
+
+        bus.send(&Request::SyncWrite {
+            addr: 0x1e,
+            len: 2,
+            entries: vec![(1, vec![0x01, 0x00]), (2, vec![0x02, 0x00])],
+        }).unwrap();
+
+        assert_eq!(bus.read(1, 0x1e, 2), vec![0x01, 0x00]);
+        assert_eq!(bus.read(2, 0x1e, 2), vec![0x02, 0x00]);
+    }
+
+    #[test]
+    fn sync_write_through_exchange_is_rejected() {
+        let mut bus = bus_with_servo(1);
+
+        let result = bus.exchange(&Request::SyncWrite {
+            addr: 0x1e,
+            len: 2,
+            entries: vec![(1, vec![0x01, 0x00])],
+        });
+
+        assert_eq!(result.err(), Some(Error::WrongExchangeMethod));
+    }
+
+    #[test]
+    fn bulk_read_through_exchange_is_rejected() {
+        let mut bus = bus_with_servo(1);
+
+        let result = bus.exchange(&Request::BulkRead { entries: vec![(1, 0x24, 2)] });
+
+        assert_eq!(result.err(), Some(Error::WrongExchangeMethod));
+    }
+
+    #[test]
+    fn bulk_read_collects_one_status_per_entry() {
+        let mut bus = bus_with_servo(1);
+        bus.add_servo(2);
+        bus.write(1, 0x24, &[0x10, 0x00]);
+        bus.write(2, 0x24, &[0x20, 0x00]);
+
+        let statuses = bus.exchange_many(
+            &Request::BulkRead { entries: vec![(1, 0x24, 2), (2, 0x24, 2)] },
+            &[1, 2],
+        ).unwrap();
+
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].data, vec![0x10, 0x00]);
+        assert_eq!(statuses[1].data, vec![0x20, 0x00]);
+    }
+}
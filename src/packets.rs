@@ -1,21 +1,223 @@
-use std;
+#[cfg(feature = "std")]
+use std::result;
+#[cfg(not(feature = "std"))]
+use core::result;
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     PacketTooShort,
     MalformedPacket,
-    InvalidCrc
+    InvalidCrc,
+
+    /// A caller-provided buffer (a `ByteSink` or a fixed-capacity status
+    /// type) was too small to hold the serialized packet.
+    BufferTooSmall,
+}
+
+/// Which Dynamixel wire protocol a packet is encoded with.
+///
+/// AX/RX/older MX servos speak Protocol 1.0 (the original `0xFF 0xFF`
+/// framing with an inverted-sum checksum); newer MX(2.0)/X/PRO servos
+/// speak Protocol 2.0 (`0xFF 0xFF 0xFD 0x00` framing, a 16-bit CRC and
+/// byte-stuffing). `Request::serialized` and `Status::from_bytes` both
+/// take a `Protocol` so the same types can drive either generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    V1,
+    V2,
+}
+
+/// Broadcast identifier: a request sent to this ID is acted on by every
+/// servo on the bus. `SyncWrite` is always framed with this ID and, like
+/// every other write to it, never elicits a `Status` reply — send it with
+/// `Bus::send`/`MockBus::send`, never `exchange`. `BulkRead` is also framed
+/// with this ID but is the one exception that does reply: once per entry
+/// in its list.
+pub const BROADCAST_ID: u8 = 0xfe;
+
+/// Maximum bytes a single `no_std` `Write`/`SyncWrite` entry's `data` can
+/// hold. Generous for the control tables this crate ships today (the
+/// widest single register write is 2 bytes); data that doesn't fit returns
+/// `Error::BufferTooSmall`.
+#[cfg(not(feature = "std"))]
+pub const MAX_WRITE_DATA: usize = 8;
+
+/// Maximum number of `(id, data)` entries a `no_std` `SyncWrite` can batch.
+#[cfg(not(feature = "std"))]
+pub const MAX_SYNC_WRITE_ENTRIES: usize = 8;
+
+/// Maximum number of `(id, addr, len)` entries a `no_std` `BulkRead` can
+/// batch.
+#[cfg(not(feature = "std"))]
+pub const MAX_BULK_READ_ENTRIES: usize = 8;
+
+/// `no_std`-friendly stand-in for a `Write`/`SyncWrite` entry's `Vec<u8>`
+/// data, held inline instead of on the heap.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub struct FixedBytes {
+    data: [u8; MAX_WRITE_DATA],
+    len: usize,
+}
+
+#[cfg(not(feature = "std"))]
+impl FixedBytes {
+    pub fn new(data: &[u8]) -> Result<FixedBytes> {
+        if data.len() > MAX_WRITE_DATA {
+            return Err(Error::BufferTooSmall);
+        }
+        let mut buf = [0u8; MAX_WRITE_DATA];
+        buf[..data.len()].copy_from_slice(data);
+        Ok(FixedBytes { data: buf, len: data.len() })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// `no_std`-friendly stand-in for `SyncWrite`'s `Vec<(u8, Vec<u8>)>`
+/// entries, held inline instead of on the heap.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub struct FixedEntries {
+    entries: [(u8, FixedBytes); MAX_SYNC_WRITE_ENTRIES],
+    len: usize,
+}
+
+#[cfg(not(feature = "std"))]
+impl Default for FixedEntries {
+    fn default() -> FixedEntries {
+        FixedEntries::new()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl FixedEntries {
+    pub fn new() -> FixedEntries {
+        let empty = FixedBytes { data: [0; MAX_WRITE_DATA], len: 0 };
+        FixedEntries { entries: [(0, empty); MAX_SYNC_WRITE_ENTRIES], len: 0 }
+    }
+
+    pub fn push(&mut self, id: u8, data: &[u8]) -> Result<()> {
+        if self.len >= MAX_SYNC_WRITE_ENTRIES {
+            return Err(Error::BufferTooSmall);
+        }
+        self.entries[self.len] = (id, FixedBytes::new(data)?);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[(u8, FixedBytes)] {
+        &self.entries[..self.len]
+    }
+}
+
+/// `no_std`-friendly stand-in for `BulkRead`'s `Vec<(u8, u8, u8)>` entries,
+/// held inline instead of on the heap.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub struct FixedBulkEntries {
+    entries: [(u8, u8, u8); MAX_BULK_READ_ENTRIES],
+    len: usize,
+}
+
+#[cfg(not(feature = "std"))]
+impl Default for FixedBulkEntries {
+    fn default() -> FixedBulkEntries {
+        FixedBulkEntries::new()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl FixedBulkEntries {
+    pub fn new() -> FixedBulkEntries {
+        FixedBulkEntries { entries: [(0, 0, 0); MAX_BULK_READ_ENTRIES], len: 0 }
+    }
+
+    pub fn push(&mut self, id: u8, addr: u8, len: u8) -> Result<()> {
+        if self.len >= MAX_BULK_READ_ENTRIES {
+            return Err(Error::BufferTooSmall);
+        }
+        self.entries[self.len] = (id, addr, len);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[(u8, u8, u8)] {
+        &self.entries[..self.len]
+    }
 }
 
 #[derive(Debug)]
 pub enum Request {
     Ping { id: u8 },
     Read { id: u8, addr: u8, len: u8 },
+
+    #[cfg(feature = "std")]
     Write { id: u8, addr: u8, data: Vec<u8> },
+    #[cfg(not(feature = "std"))]
+    Write { id: u8, addr: u8, data: FixedBytes },
+
+    /// Writes `entries` in a single broadcast packet: every `(id, data)`
+    /// pair writes `data` to `addr` on that servo. Each `data` must be
+    /// exactly `len` bytes long.
+    #[cfg(feature = "std")]
+    SyncWrite { addr: u8, len: u8, entries: Vec<(u8, Vec<u8>)> },
+    #[cfg(not(feature = "std"))]
+    SyncWrite { addr: u8, len: u8, entries: FixedEntries },
+
+    /// Reads a possibly different address/length from each servo named in
+    /// `entries` as `(id, addr, len)`, in a single broadcast packet.
+    #[cfg(feature = "std")]
+    BulkRead { entries: Vec<(u8, u8, u8)> },
+    #[cfg(not(feature = "std"))]
+    BulkRead { entries: FixedBulkEntries },
+}
+
+/// Checks whether `serialized` holds exactly one complete status packet
+/// for `protocol` (no more, no less) — used by both `Status` and
+/// `StatusBuf`, and by `Bus` to know when a response has fully arrived.
+pub fn is_constructible_from(serialized: &[u8], protocol: Protocol) -> bool {
+    let len = serialized.len();
+    match declared_length(serialized, protocol) {
+        Some(declared_len) => len == declared_len,
+        None => false,
+    }
+}
+
+/// Like `is_constructible_from`, but returns the expected total length
+/// instead of a bool, so callers can peel a single packet off the front
+/// of a buffer that may hold more than one reply.
+pub(crate) fn declared_length(serialized: &[u8], protocol: Protocol) -> Option<usize> {
+    match protocol {
+        Protocol::V1 => {
+            if serialized.len() < 6 {
+                None
+            } else {
+                Some(serialized[3] as usize + 4)
+            }
+        }
+        Protocol::V2 => {
+            if serialized.len() < 7 {
+                None
+            } else {
+                let declared = u16::from(serialized[5]) | (u16::from(serialized[6]) << 8);
+                if declared < 4 {
+                    None
+                } else {
+                    Some(7 + declared as usize)
+                }
+            }
+        }
+    }
 }
 
+/// Status reply backed by a heap-allocated `Vec<u8>`. Available under the
+/// `std` feature; `no_std` callers use `StatusBuf` instead.
+#[cfg(feature = "std")]
 #[derive(Debug, PartialEq)]
 pub struct Status {
     pub id: u8,
@@ -23,9 +225,17 @@ pub struct Status {
     pub data: Vec<u8>
 }
 
+#[cfg(feature = "std")]
 impl Status {
-    pub fn from_bytes(serialized: &[u8]) -> Result<Status> {
-        if !Status::is_constructible_from(serialized) {
+    pub fn from_bytes(serialized: &[u8], protocol: Protocol) -> Result<Status> {
+        match protocol {
+            Protocol::V1 => Status::from_bytes_v1(serialized),
+            Protocol::V2 => Status::from_bytes_v2(serialized),
+        }
+    }
+
+    fn from_bytes_v1(serialized: &[u8]) -> Result<Status> {
+        if !is_constructible_from(serialized, Protocol::V1) {
             return Err(Error::PacketTooShort);
         }
 
@@ -43,32 +253,190 @@ impl Status {
         Ok(Status { id: serialized[2], error: serialized[4], data: d })
     }
 
-    pub fn is_constructible_from(serialized: &[u8]) -> bool {
+    fn from_bytes_v2(serialized: &[u8]) -> Result<Status> {
+        if !is_constructible_from(serialized, Protocol::V2) {
+            return Err(Error::PacketTooShort);
+        }
+
         let len = serialized.len();
-        match Status::extract_declared_length(serialized) {
-            Some(declared_len) => {
-                len == declared_len
-            }
-            None => {
-                false
+        let actual_crc = crc16(&serialized[0..len - 2]);
+        let declared_crc = u16::from(serialized[len - 2]) | (u16::from(serialized[len - 1]) << 8);
+        if declared_crc != actual_crc {
+            return Err(Error::InvalidCrc);
+        }
+
+        let params = unstuff(&serialized[9..len - 2]);
+        Ok(Status { id: serialized[4], error: serialized[8], data: params })
+    }
+
+    /// Parameter bytes of this reply, for callers that want to treat
+    /// `Status` and the `no_std` `StatusBuf` uniformly.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(feature = "std")]
+impl Readable for Status {
+    fn read_from(serialized: &[u8], protocol: Protocol) -> Result<Status> {
+        Status::from_bytes(serialized, protocol)
+    }
+}
+
+/// Maximum parameter bytes a `no_std` status reply can hold without an
+/// allocator. Generous for the control tables this crate ships today (the
+/// widest single register read is 2 bytes); replies with more parameters
+/// than this return `Error::BufferTooSmall`.
+pub const MAX_STATUS_DATA: usize = 32;
+
+/// Maximum instruction+parameter bytes `Request::body` can stage on the
+/// stack while serializing without an allocator. Generously covers every
+/// request this crate builds today; requests needing more (e.g. a
+/// `SyncWrite` across many servos) return `Error::BufferTooSmall`.
+pub const MAX_BODY_LEN: usize = 64;
+
+/// `no_std`-friendly counterpart to `Status`: carries its parameter bytes
+/// in an inline buffer instead of a heap-allocated `Vec`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusBuf {
+    pub id: u8,
+    pub error: u8,
+    data: [u8; MAX_STATUS_DATA],
+    len: usize,
+}
+
+impl StatusBuf {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn from_bytes_v1(serialized: &[u8]) -> Result<StatusBuf> {
+        if !is_constructible_from(serialized, Protocol::V1) {
+            return Err(Error::PacketTooShort);
+        }
+
+        let len = serialized.len();
+        let actual_crc = crc(&serialized[0..len - 1]);
+        if serialized[len - 1] != actual_crc {
+            return Err(Error::InvalidCrc);
+        }
+
+        let mut data = [0u8; MAX_STATUS_DATA];
+        let mut n = 0usize;
+        if len > 4 {
+            let src = &serialized[5..len - 1];
+            if src.len() > MAX_STATUS_DATA {
+                return Err(Error::BufferTooSmall);
             }
+            data[..src.len()].copy_from_slice(src);
+            n = src.len();
         }
+        Ok(StatusBuf { id: serialized[2], error: serialized[4], data, len: n })
     }
 
-    fn extract_declared_length(serialized: &[u8]) -> Option<usize> {
+    fn from_bytes_v2(serialized: &[u8]) -> Result<StatusBuf> {
+        if !is_constructible_from(serialized, Protocol::V2) {
+            return Err(Error::PacketTooShort);
+        }
+
         let len = serialized.len();
-        if len < 6 {
-            None
-        } else {
-            Some((serialized[3] + 4) as usize)
+        let actual_crc = crc16(&serialized[0..len - 2]);
+        let declared_crc = u16::from(serialized[len - 2]) | (u16::from(serialized[len - 1]) << 8);
+        if declared_crc != actual_crc {
+            return Err(Error::InvalidCrc);
+        }
+
+        let stuffed = &serialized[9..len - 2];
+        let mut data = [0u8; MAX_STATUS_DATA];
+        let mut n = 0usize;
+        let mut i = 0;
+        while i < stuffed.len() {
+            if n >= MAX_STATUS_DATA {
+                return Err(Error::BufferTooSmall);
+            }
+            data[n] = stuffed[i];
+            n += 1;
+            if n >= 3 && data[n - 3] == 0xff && data[n - 2] == 0xff && data[n - 1] == 0xfd
+                && i + 1 < stuffed.len() && stuffed[i + 1] == 0xfd {
+                i += 1;
+            }
+            i += 1;
+        }
+        Ok(StatusBuf { id: serialized[4], error: serialized[8], data, len: n })
+    }
+}
+
+impl Readable for StatusBuf {
+    fn read_from(serialized: &[u8], protocol: Protocol) -> Result<StatusBuf> {
+        match protocol {
+            Protocol::V1 => StatusBuf::from_bytes_v1(serialized),
+            Protocol::V2 => StatusBuf::from_bytes_v2(serialized),
         }
     }
 }
 
+/// Deserializes a packet from a byte slice. Implemented by both the
+/// heap-backed `Status` (under the `std` feature), which allocates its
+/// `data`, and the inline-buffer `StatusBuf`, which does not allocate.
+pub trait Readable: Sized {
+    fn read_from(serialized: &[u8], protocol: Protocol) -> Result<Self>;
+}
+
+/// Minimal output sink `Writeable` serializes into, so a packet can be
+/// built without allocating. Implemented for `SliceSink` (no allocator
+/// required) and, under the `std` feature, for `Vec<u8>`.
+pub trait ByteSink {
+    fn push(&mut self, byte: u8) -> Result<()>;
+}
+
+/// Writes into a caller-provided `&mut [u8]`, failing with
+/// `Error::BufferTooSmall` instead of growing.
+pub struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceSink<'a> {
+    pub fn new(buf: &'a mut [u8]) -> SliceSink<'a> {
+        SliceSink { buf, len: 0 }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<'a> ByteSink for SliceSink<'a> {
+    fn push(&mut self, byte: u8) -> Result<()> {
+        if self.len >= self.buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ByteSink for Vec<u8> {
+    fn push(&mut self, byte: u8) -> Result<()> {
+        Vec::push(self, byte);
+        Ok(())
+    }
+}
+
+/// Serializes a packet into a `ByteSink`, without allocating. Implemented
+/// for `Request`; the existing `Vec`-returning `serialized` stays
+/// available under the `std` feature for callers that don't care.
+pub trait Writeable {
+    fn write_to<S: ByteSink>(&self, out: &mut S, protocol: Protocol) -> Result<usize>;
+}
+
 impl Request {
     fn id_byte(&self) -> u8 {
         match *self {
             Request::Ping { id } | Request::Write { id, .. } | Request::Read { id, .. } => id,
+            Request::SyncWrite { .. } | Request::BulkRead { .. } => BROADCAST_ID,
         }
     }
 
@@ -77,37 +445,262 @@ impl Request {
             Request::Ping { .. } => 0x01,
             Request::Read { .. } => 0x02,
             Request::Write { .. } => 0x03,
+            Request::SyncWrite { .. } => 0x83,
+            Request::BulkRead { .. } => 0x92,
         }
     }
 
-    fn len_byte(&self) -> u8 {
-        match *self {
-            Request::Ping { .. } => 2,
-            Request::Read { .. } => 4,
-            Request::Write { ref data, .. } => (data.len() + 3) as u8,
+    /// Stages the instruction+parameter bytes on the stack so the caller
+    /// can stuff/checksum them without allocating.
+    ///
+    /// `SyncWrite`/`BulkRead` differ between protocols: Protocol 1.0 packs
+    /// `addr`/`len` as single bytes and orders `BulkRead` entries as a
+    /// dummy byte followed by `(len, id, addr)` triples, while Protocol
+    /// 2.0 packs `addr`/`len` as 2-byte little-endian fields and orders
+    /// `BulkRead` entries as `(id, addr_l, addr_h, len_l, len_h)` with no
+    /// leading dummy byte.
+    fn body(&self, protocol: Protocol) -> Result<([u8; MAX_BODY_LEN], usize)> {
+        let mut buf = [0u8; MAX_BODY_LEN];
+        let mut len = 0usize;
+        {
+            let mut push = |b: u8| -> Result<()> {
+                if len >= MAX_BODY_LEN {
+                    return Err(Error::BufferTooSmall);
+                }
+                buf[len] = b;
+                len += 1;
+                Ok(())
+            };
+
+            push(self.instruction_byte())?;
+            match *self {
+                Request::Write { addr, ref data, .. } => {
+                    push(addr)?;
+                    for &b in data.as_slice() {
+                        push(b)?;
+                    }
+                }
+                Request::Read { addr, len: rlen, .. } => {
+                    push(addr)?;
+                    push(rlen)?;
+                }
+                Request::Ping { .. } => {}
+                Request::SyncWrite { addr, len: slen, ref entries } => {
+                    push(addr)?;
+                    if let Protocol::V2 = protocol {
+                        push(0x00)?;
+                    }
+                    push(slen)?;
+                    if let Protocol::V2 = protocol {
+                        push(0x00)?;
+                    }
+                    for &(id, ref data) in entries.as_slice() {
+                        push(id)?;
+                        for &b in data.as_slice() {
+                            push(b)?;
+                        }
+                    }
+                }
+                Request::BulkRead { ref entries } => match protocol {
+                    Protocol::V1 => {
+                        push(0x00)?;
+                        for &(id, addr, elen) in entries.as_slice() {
+                            push(elen)?;
+                            push(id)?;
+                            push(addr)?;
+                        }
+                    }
+                    Protocol::V2 => {
+                        for &(id, addr, elen) in entries.as_slice() {
+                            push(id)?;
+                            push(addr)?;
+                            push(0x00)?;
+                            push(elen)?;
+                            push(0x00)?;
+                        }
+                    }
+                },
+            }
         }
+        Ok((buf, len))
     }
 
-    pub fn serialized(&self) -> Vec<u8> {
-        let mut v = vec![0xff, 0xff];
-        v.push(self.id_byte());
-        v.push(self.len_byte());
-        v.push(self.instruction_byte());
+    /// Builds a `Write` request, failing with `Error::BufferTooSmall`
+    /// instead of silently truncating `data` when it can't fit in a
+    /// `no_std` build's fixed-capacity buffer.
+    pub fn write(id: u8, addr: u8, data: &[u8]) -> Result<Request> {
+        #[cfg(feature = "std")]
+        { Ok(Request::Write { id, addr, data: data.to_vec() }) }
+        #[cfg(not(feature = "std"))]
+        { Ok(Request::Write { id, addr, data: FixedBytes::new(data)? }) }
+    }
+
+    fn write_to_v1<S: ByteSink>(&self, out: &mut S) -> Result<usize> {
+        let (body, body_len) = self.body(Protocol::V1)?;
+        let id = self.id_byte();
+        let len = (body_len + 1) as u8;
+
+        out.push(0xff)?;
+        out.push(0xff)?;
+        out.push(id)?;
+        out.push(len)?;
+
+        let mut sum = id.wrapping_add(len);
+        for &b in &body[..body_len] {
+            out.push(b)?;
+            sum = sum.wrapping_add(b);
+        }
+
+        out.push(!sum)?;
+        Ok(5 + body_len)
+    }
+
+    fn write_to_v2<S: ByteSink>(&self, out: &mut S) -> Result<usize> {
+        let (body, body_len) = self.body(Protocol::V2)?;
+
+        let mut stuffed_len = body_len;
+        for i in 0..body_len {
+            if i >= 2 && body[i] == 0xfd && body[i - 1] == 0xff && body[i - 2] == 0xff {
+                stuffed_len += 1;
+            }
+        }
+        let len_field = (stuffed_len + 2) as u16;
+
+        let mut crc: u16 = 0;
+        push_crc16(out, &mut crc, 0xff)?;
+        push_crc16(out, &mut crc, 0xff)?;
+        push_crc16(out, &mut crc, 0xfd)?;
+        push_crc16(out, &mut crc, 0x00)?;
+        push_crc16(out, &mut crc, self.id_byte())?;
+        push_crc16(out, &mut crc, (len_field & 0xff) as u8)?;
+        push_crc16(out, &mut crc, (len_field >> 8) as u8)?;
+
+        for i in 0..body_len {
+            if i >= 2 && body[i] == 0xfd && body[i - 1] == 0xff && body[i - 2] == 0xff {
+                push_crc16(out, &mut crc, 0xfd)?;
+            }
+            push_crc16(out, &mut crc, body[i])?;
+        }
+
+        out.push((crc & 0xff) as u8)?;
+        out.push((crc >> 8) as u8)?;
+
+        Ok(7 + stuffed_len + 2)
+    }
+}
+
+impl Writeable for Request {
+    /// Serializes `self` into `out` without allocating, by staging the
+    /// instruction+parameter bytes on the stack and streaming the rest
+    /// (header, byte-stuffing, CRC) straight into the sink.
+    fn write_to<S: ByteSink>(&self, out: &mut S, protocol: Protocol) -> Result<usize> {
+        match protocol {
+            Protocol::V1 => self.write_to_v1(out),
+            Protocol::V2 => self.write_to_v2(out),
+        }
+    }
+}
+
+fn push_crc16<S: ByteSink>(out: &mut S, crc: &mut u16, byte: u8) -> Result<()> {
+    *crc ^= (byte as u16) << 8;
+    for _ in 0..8 {
+        *crc = if *crc & 0x8000 != 0 { (*crc << 1) ^ 0x8005 } else { *crc << 1 };
+    }
+    out.push(byte)
+}
+
+#[cfg(feature = "std")]
+impl Request {
+    fn len_byte(&self, protocol: Protocol) -> u8 {
+        (self.params(protocol).len() + 2) as u8
+    }
+
+    /// See `body`'s doc comment for how `SyncWrite`/`BulkRead` differ
+    /// between protocols.
+    fn params(&self, protocol: Protocol) -> Vec<u8> {
+        let mut params = vec![];
         match *self {
             Request::Write { addr, ref data, .. } => {
-                v.push(addr);
-                v.extend(data);
+                params.push(addr);
+                params.extend(data);
             }
             Request::Read { addr, len, .. } => {
-                v.push(addr);
-                v.push(len);
+                params.push(addr);
+                params.push(len);
             }
-            _ => {}
+            Request::Ping { .. } => {}
+            Request::SyncWrite { addr, len, ref entries } => {
+                params.push(addr);
+                if let Protocol::V2 = protocol {
+                    params.push(0x00);
+                }
+                params.push(len);
+                if let Protocol::V2 = protocol {
+                    params.push(0x00);
+                }
+                for &(id, ref data) in entries {
+                    params.push(id);
+                    params.extend(data);
+                }
+            }
+            Request::BulkRead { ref entries } => match protocol {
+                Protocol::V1 => {
+                    params.push(0x00);
+                    for &(id, addr, len) in entries {
+                        params.push(len);
+                        params.push(id);
+                        params.push(addr);
+                    }
+                }
+                Protocol::V2 => {
+                    for &(id, addr, len) in entries {
+                        params.push(id);
+                        params.push(addr);
+                        params.push(0x00);
+                        params.push(len);
+                        params.push(0x00);
+                    }
+                }
+            },
+        }
+        params
+    }
+
+    pub fn serialized(&self, protocol: Protocol) -> Vec<u8> {
+        match protocol {
+            Protocol::V1 => self.serialized_v1(),
+            Protocol::V2 => self.serialized_v2(),
         }
+    }
+
+    fn serialized_v1(&self) -> Vec<u8> {
+        let mut v = vec![0xff, 0xff];
+        v.push(self.id_byte());
+        v.push(self.len_byte(Protocol::V1));
+        v.push(self.instruction_byte());
+        v.extend(self.params(Protocol::V1));
         let crc = crc(&v);
         v.push(crc);
         v
     }
+
+    fn serialized_v2(&self) -> Vec<u8> {
+        let mut body = vec![self.instruction_byte()];
+        body.extend(self.params(Protocol::V2));
+        let stuffed_body = stuff(&body);
+
+        let len = (stuffed_body.len() + 2) as u16;
+        let mut v = vec![0xff, 0xff, 0xfd, 0x00];
+        v.push(self.id_byte());
+        v.push((len & 0xff) as u8);
+        v.push((len >> 8) as u8);
+        v.extend(stuffed_body);
+
+        let crc = crc16(&v);
+        v.push((crc & 0xff) as u8);
+        v.push((crc >> 8) as u8);
+        v
+    }
 }
 
 fn crc(serialized: &[u8]) -> u8 {
@@ -122,9 +715,59 @@ fn crc_data(data: &[u8]) -> u8 {
     return !sum;
 }
 
-#[cfg(test)]
+/// Protocol 2.0 CRC-16/BUYPASS checksum: polynomial 0x8005, MSB-first,
+/// zero initial value, no input/output reflection. Computed over the
+/// whole stuffed packet from the first header byte through the last
+/// parameter byte.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Inserts an extra `0xFD` after every `0xFF 0xFF 0xFD` run in `data`, so a
+/// packet body can never be mistaken for the start of a new Protocol 2.0
+/// frame while in flight.
+#[cfg(feature = "std")]
+fn stuff(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        out.push(b);
+        if i >= 2 && b == 0xfd && data[i - 1] == 0xff && data[i - 2] == 0xff {
+            out.push(0xfd);
+        }
+    }
+    out
+}
+
+/// Reverses `stuff`, dropping the extra `0xFD` inserted after each
+/// `0xFF 0xFF 0xFD` run.
+#[cfg(feature = "std")]
+fn unstuff(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        out.push(data[i]);
+        let n = out.len();
+        if n >= 3 && out[n - 3] == 0xff && out[n - 2] == 0xff && out[n - 1] == 0xfd
+            && i + 1 < data.len() && data[i + 1] == 0xfd {
+            i += 1;
+        }
+        i += 1;
+    }
+    out
+}
+
+// `Vec`-based `Request` literals below only exist under `feature = "std"`;
+// the `no_std` fixed-capacity path is covered separately by `no_std_tests`.
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use super::{crc_data, Request, Status, Error};
+    use super::{crc_data, crc16, stuff, unstuff, Error, Protocol, Readable, Request, SliceSink, Status, StatusBuf, Writeable};
 
     #[test]
     fn crc_is_calculated_correctly_from_primitive_data() {
@@ -143,7 +786,7 @@ mod tests {
         //from doc
         let p = Request::Ping { id: 0x01 };
         let expected: Vec<u8> = vec![0xff, 0xff, 0x01, 0x02, 0x01];
-        assert_eq!(expected[0..5], p.serialized()[0..5])
+        assert_eq!(expected[0..5], p.serialized(Protocol::V1)[0..5])
     }
 
     #[test]
@@ -151,53 +794,306 @@ mod tests {
         //from doc
         let p = Request::Ping { id: 0x03 };
         let expected: Vec<u8> = vec![0xff, 0xff, 0x03, 0x02, 0x01, 0xf9];
-        assert_eq!(expected[0..5], p.serialized()[0..5])
+        assert_eq!(expected[0..5], p.serialized(Protocol::V1)[0..5])
     }
 
     #[test]
     fn write_is_serialized_correctly() {
         //from doc
         let expected: Vec<u8> = vec![0xff, 0xff, 0xfe, 0x04, 0x03, 0x03, 0x01, 0xf6];
-        assert_eq!(expected, Request::Write { id: 0x0fe, addr: 0x03, data: vec![0x01] }.serialized())
+        assert_eq!(expected, Request::Write { id: 0x0fe, addr: 0x03, data: vec![0x01] }.serialized(Protocol::V1))
     }
 
     #[test]
     fn read_is_serialized_correctly() {
         //from doc
         let expected: Vec<u8> = vec![0xff, 0xff, 0x01, 0x04, 0x02, 0x2b, 0x01, 0xcc];
-        assert_eq!(expected, Request::Read { id: 0x01, addr: 0x2b, len: 0x01 }.serialized())
+        assert_eq!(expected, Request::Read { id: 0x01, addr: 0x2b, len: 0x01 }.serialized(Protocol::V1))
     }
 
     #[test]
     fn status_is_deserialized_correctly() {
         //from doc
         let input: Vec<u8> = vec![0xff, 0xff, 0x01, 0x02, 0x24, 0xd8];
-        let status = Status::from_bytes(&input).unwrap();
+        let status = Status::from_bytes(&input, Protocol::V1).unwrap();
         assert_eq!(status, Status { id: 0x01, error: 0x24, data: vec![] })
     }
 
     #[test]
     fn status_with_data_is_deserialized_correctly() {
         let input: Vec<u8> = vec![0xff, 0xff, 0x01, 0x06, 0x24, 0x00, 0x00, 0x00, 0x00, 0xd4];
-        let status = Status::from_bytes(&input).unwrap();
+        let status = Status::from_bytes(&input, Protocol::V1).unwrap();
         assert_eq!(status, Status { id: 0x01, error: 0x24, data: vec![0x00; 4] })
     }
 
     #[test]
     fn status_from_bytes_returns_too_short_error_when_too_few_bytes_provided() {
         let input: Vec<u8> = vec![0xff, 0xff, 0x01];
-        assert_eq!(Status::from_bytes(&input).err(), Some(Error::PacketTooShort))
+        assert_eq!(Status::from_bytes(&input, Protocol::V1).err(), Some(Error::PacketTooShort))
     }
 
     #[test]
     fn status_from_bytes_returns_too_short_error_when_invalid_length_is_provided() {
         let input: Vec<u8> = vec![0xff, 0xff, 0x01, 0x06, 0x24, 0xd8];
-        assert_eq!(Status::from_bytes(&input).err(), Some(Error::PacketTooShort))
+        assert_eq!(Status::from_bytes(&input, Protocol::V1).err(), Some(Error::PacketTooShort))
     }
 
     #[test]
     fn status_from_bytes_returns_invalid_crc_for_corrupt_packet() {
         let input: Vec<u8> = vec![0xff, 0xff, 0x01, 0x02, 0x24, 0xff];
-        assert_eq!(Status::from_bytes(&input).err(), Some(Error::InvalidCrc))
+        assert_eq!(Status::from_bytes(&input, Protocol::V1).err(), Some(Error::InvalidCrc))
+    }
+
+    #[test]
+    fn crc16_is_calculated_correctly_from_primitive_data() {
+        assert_eq!(crc16(&[0xff]), 0x0202);
+        assert_eq!(crc16(&[0x01, 0x01, 0x01]), 0x8611);
+    }
+
+    #[test]
+    fn byte_stuffing_inserts_extra_0xfd_after_header_like_run() {
+        let stuffed = stuff(&[0xff, 0xff, 0xfd, 0x01]);
+        assert_eq!(stuffed, vec![0xff, 0xff, 0xfd, 0xfd, 0x01]);
+    }
+
+    #[test]
+    fn byte_unstuffing_reverses_stuffing() {
+        let stuffed = stuff(&[0x00, 0xff, 0xff, 0xfd, 0xff, 0xff, 0xfd, 0x02]);
+        assert_eq!(unstuff(&stuffed), vec![0x00, 0xff, 0xff, 0xfd, 0xff, 0xff, 0xfd, 0x02]);
+    }
+
+    #[test]
+    fn ping_is_serialized_correctly_as_protocol_v2() {
+        let p = Request::Ping { id: 0x01 };
+        let expected: Vec<u8> = vec![0xff, 0xff, 0xfd, 0x00, 0x01, 0x03, 0x00, 0x01, 0x19, 0x4e];
+        assert_eq!(expected, p.serialized(Protocol::V2))
+    }
+
+    #[test]
+    fn write_is_serialized_correctly_as_protocol_v2() {
+        let w = Request::Write { id: 0x01, addr: 0x03, data: vec![0x01] };
+        let expected: Vec<u8> = vec![0xff, 0xff, 0xfd, 0x00, 0x01, 0x05, 0x00, 0x03, 0x03, 0x01, 0x6e, 0xaf];
+        assert_eq!(expected, w.serialized(Protocol::V2))
+    }
+
+    #[test]
+    fn status_is_deserialized_correctly_as_protocol_v2() {
+        let input: Vec<u8> = vec![0xff, 0xff, 0xfd, 0x00, 0x01, 0x04, 0x00, 0x55, 0x00, 0xa1, 0x0c];
+        let status = Status::from_bytes(&input, Protocol::V2).unwrap();
+        assert_eq!(status, Status { id: 0x01, error: 0x00, data: vec![] })
+    }
+
+    #[test]
+    fn status_with_data_is_deserialized_correctly_as_protocol_v2() {
+        let input: Vec<u8> = vec![0xff, 0xff, 0xfd, 0x00, 0x01, 0x06, 0x00, 0x55, 0x00, 0x10, 0x20, 0x06, 0xbb];
+        let status = Status::from_bytes(&input, Protocol::V2).unwrap();
+        assert_eq!(status, Status { id: 0x01, error: 0x00, data: vec![0x10, 0x20] })
+    }
+
+    #[test]
+    fn sync_write_is_serialized_correctly() {
+        let req = Request::SyncWrite {
+            addr: 0x1e,
+            len: 0x01,
+            entries: vec![(0x01, vec![0x10]), (0x02, vec![0x20])],
+        };
+        let expected: Vec<u8> = vec![0xff, 0xff, 0xfe, 0x08, 0x83, 0x1e, 0x01, 0x01, 0x10, 0x02, 0x20, 0x24];
+        assert_eq!(expected, req.serialized(Protocol::V1))
+    }
+
+    #[test]
+    fn bulk_read_is_serialized_correctly() {
+        let req = Request::BulkRead { entries: vec![(0x01, 0x24, 0x02), (0x02, 0x24, 0x02)] };
+        let expected: Vec<u8> = vec![0xff, 0xff, 0xfe, 0x09, 0x92, 0x00, 0x02, 0x01, 0x24, 0x02, 0x02, 0x24, 0x17];
+        assert_eq!(expected, req.serialized(Protocol::V1))
+    }
+
+    #[test]
+    fn sync_write_is_serialized_correctly_as_protocol_v2() {
+        // Real 2.0 SYNC_WRITE parameters are 2-byte little-endian addr/len,
+        // unlike V1's single bytes.
+        let req = Request::SyncWrite {
+            addr: 0x1e,
+            len: 0x01,
+            entries: vec![(0x01, vec![0x10]), (0x02, vec![0x20])],
+        };
+        let expected: Vec<u8> = vec![
+            0xff, 0xff, 0xfd, 0x00, 0xfe, 0x0b, 0x00, 0x83, 0x1e, 0x00, 0x01, 0x00, 0x01, 0x10, 0x02, 0x20, 0x8f,
+            0x84,
+        ];
+        assert_eq!(expected, req.serialized(Protocol::V2))
+    }
+
+    #[test]
+    fn bulk_read_is_serialized_correctly_as_protocol_v2() {
+        // Real 2.0 BULK_READ has no leading dummy byte and orders each
+        // entry as (id, addr_l, addr_h, len_l, len_h), unlike V1's dummy
+        // byte followed by (len, id, addr) triples.
+        let req = Request::BulkRead { entries: vec![(0x01, 0x24, 0x02), (0x02, 0x24, 0x02)] };
+        let expected: Vec<u8> = vec![
+            0xff, 0xff, 0xfd, 0x00, 0xfe, 0x0d, 0x00, 0x92, 0x01, 0x24, 0x00, 0x02, 0x00, 0x02, 0x24, 0x00, 0x02,
+            0x00, 0xa5, 0xb4,
+        ];
+        assert_eq!(expected, req.serialized(Protocol::V2))
+    }
+
+    #[test]
+    fn status_from_bytes_returns_too_short_error_for_protocol_v2_declared_length_under_minimum() {
+        // A real status frame needs instruction+error+2-byte CRC, so a V2
+        // length field declaring fewer than 4 bytes (even on an otherwise
+        // well-formed, all-zero buffer a glitched line can produce) must be
+        // rejected before `from_bytes_v2` slices the parameter range,
+        // rather than panicking.
+        let input: Vec<u8> = vec![0x00; 7];
+        assert_eq!(Status::from_bytes(&input, Protocol::V2).err(), Some(Error::PacketTooShort));
+        assert_eq!(StatusBuf::read_from(&input, Protocol::V2).err(), Some(Error::PacketTooShort));
+    }
+
+    #[test]
+    fn status_from_bytes_returns_invalid_crc_for_corrupt_protocol_v2_packet() {
+        let input: Vec<u8> = vec![0xff, 0xff, 0xfd, 0x00, 0x01, 0x04, 0x00, 0x55, 0x00, 0x00, 0x00];
+        assert_eq!(Status::from_bytes(&input, Protocol::V2).err(), Some(Error::InvalidCrc))
+    }
+
+    #[test]
+    fn write_to_matches_vec_based_serialization_for_v1_and_v2() {
+        let requests = vec![
+            Request::Ping { id: 0x03 },
+            Request::Read { id: 0x01, addr: 0x2b, len: 0x01 },
+            Request::Write { id: 0x0fe, addr: 0x03, data: vec![0x01] },
+            // Triggers v2 byte-stuffing: the data bytes contain a
+            // `0xFF 0xFF 0xFD` run, which both `write_to_v2` and
+            // `serialized_v2` must escape with an extra `0xFD` the same way.
+            Request::Write { id: 0x01, addr: 0x10, data: vec![0xff, 0xff, 0xfd, 0xaa] },
+            Request::SyncWrite { addr: 0x1e, len: 0x01, entries: vec![(0x01, vec![0x10]), (0x02, vec![0x20])] },
+            Request::BulkRead { entries: vec![(0x01, 0x24, 0x02), (0x02, 0x24, 0x02)] },
+        ];
+
+        for protocol in &[Protocol::V1, Protocol::V2] {
+            for req in &requests {
+                let mut buf = [0u8; 64];
+                let mut sink = SliceSink::new(&mut buf);
+                let written = req.write_to(&mut sink, *protocol).unwrap();
+                assert_eq!(written, sink.as_slice().len());
+                assert_eq!(req.serialized(*protocol), sink.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn slice_sink_reports_buffer_too_small_instead_of_growing() {
+        let mut buf = [0u8; 2];
+        let mut sink = SliceSink::new(&mut buf);
+        let req = Request::Ping { id: 0x01 };
+        assert_eq!(req.write_to(&mut sink, Protocol::V1).err(), Some(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn status_buf_round_trips_with_status_for_v1_and_v2() {
+        for protocol in &[Protocol::V1, Protocol::V2] {
+            let req = Request::Read { id: 0x01, addr: 0x24, len: 0x04 };
+            let mut buf = [0u8; 64];
+            let mut sink = SliceSink::new(&mut buf);
+            req.write_to(&mut sink, *protocol).unwrap();
+
+            let reply: Vec<u8> = match protocol {
+                Protocol::V1 => vec![0xff, 0xff, 0x01, 0x06, 0x00, 0x0a, 0x0b, 0x0c, 0x0d, 0x00],
+                Protocol::V2 => vec![0xff, 0xff, 0xfd, 0x00, 0x01, 0x08, 0x00, 0x55, 0x00, 0x0a, 0x0b, 0x0c, 0x0d, 0x00, 0x00],
+            };
+            // Fix up the checksum so each fixture is a valid packet for its protocol.
+            let reply = fixed_up_reply(reply, *protocol);
+
+            let status = Status::from_bytes(&reply, *protocol).unwrap();
+            let status_buf = StatusBuf::read_from(&reply, *protocol).unwrap();
+            assert_eq!(status.id, status_buf.id);
+            assert_eq!(status.error, status_buf.error);
+            assert_eq!(status.data.as_slice(), status_buf.data());
+        }
+    }
+
+    fn fixed_up_reply(mut reply: Vec<u8>, protocol: Protocol) -> Vec<u8> {
+        match protocol {
+            Protocol::V1 => {
+                let len = reply.len();
+                let crc = crc_data(&reply[2..len - 1]);
+                reply[len - 1] = crc;
+            }
+            Protocol::V2 => {
+                let len = reply.len();
+                let crc = crc16(&reply[0..len - 2]);
+                reply[len - 2] = (crc & 0xff) as u8;
+                reply[len - 1] = (crc >> 8) as u8;
+            }
+        }
+        reply
+    }
+}
+
+/// Covers the fixed-capacity `no_std` path (`FixedBytes`/`FixedEntries`/
+/// `FixedBulkEntries`, and `Request::write`'s `no_std` branch) that `tests`
+/// above never builds under `feature = "std"`. Run with
+/// `cargo test --no-default-features`.
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests {
+    use super::{
+        Error, FixedBulkEntries, FixedBytes, FixedEntries, Protocol, Request, SliceSink, Writeable,
+        MAX_BULK_READ_ENTRIES, MAX_SYNC_WRITE_ENTRIES, MAX_WRITE_DATA,
+    };
+
+    #[test]
+    fn fixed_bytes_rejects_data_past_capacity() {
+        let data = [0u8; MAX_WRITE_DATA + 1];
+        assert_eq!(FixedBytes::new(&data).err(), Some(Error::BufferTooSmall));
+    }
+
+    #[test]
+    fn fixed_entries_push_past_capacity_returns_buffer_too_small() {
+        let mut entries = FixedEntries::new();
+        for id in 0..MAX_SYNC_WRITE_ENTRIES as u8 {
+            entries.push(id, &[0x01]).unwrap();
+        }
+        assert_eq!(entries.push(0xff, &[0x01]).err(), Some(Error::BufferTooSmall));
+        assert_eq!(entries.as_slice().len(), MAX_SYNC_WRITE_ENTRIES);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fixed_bulk_entries_push_past_capacity_returns_buffer_too_small() {
+        let mut entries = FixedBulkEntries::new();
+        for id in 0..MAX_BULK_READ_ENTRIES as u8 {
+            entries.push(id, 0x24, 0x02).unwrap();
+        }
+        assert_eq!(entries.push(0xff, 0x24, 0x02).err(), Some(Error::BufferTooSmall));
+        assert_eq!(entries.as_slice().len(), MAX_BULK_READ_ENTRIES);
+    }
+
+    #[test]
+    fn write_request_with_fixed_bytes_writes_to_a_slice_sink() {
+        // Same fixture as `write_is_serialized_correctly` in `tests`, built
+        // through the `no_std` `FixedBytes`-backed `Request::write` instead.
+        let req = Request::write(0x0fe, 0x03, &[0x01]).unwrap();
+        let mut buf = [0u8; 64];
+        let mut sink = SliceSink::new(&mut buf);
+
+        let written = req.write_to(&mut sink, Protocol::V1).unwrap();
+
+        let expected: [u8; 8] = [0xff, 0xff, 0xfe, 0x04, 0x03, 0x03, 0x01, 0xf6];
+        assert_eq!(written, expected.len());
+        assert_eq!(sink.as_slice(), &expected);
+    }
+
+    #[test]
+    fn sync_write_with_fixed_entries_writes_to_a_slice_sink() {
+        let mut entries = FixedEntries::new();
+        entries.push(0x01, &[0x10]).unwrap();
+        entries.push(0x02, &[0x20]).unwrap();
+        let req = Request::SyncWrite { addr: 0x1e, len: 0x01, entries };
+
+        let mut buf = [0u8; 64];
+        let mut sink = SliceSink::new(&mut buf);
+        let written = req.write_to(&mut sink, Protocol::V1).unwrap();
+
+        let expected: [u8; 12] = [0xff, 0xff, 0xfe, 0x08, 0x83, 0x1e, 0x01, 0x01, 0x10, 0x02, 0x20, 0x24];
+        assert_eq!(written, expected.len());
+        assert_eq!(sink.as_slice(), &expected);
+    }
+}